@@ -1,3 +1,5 @@
+use std::io;
+
 // The algorithm uses at most sniffLen bytes to make its decision.
 const SNIFF_LEN: usize = 512;
 
@@ -10,6 +12,354 @@ a valid MIME type: if it cannot determine a more specific one, it
 returns "application/octet-stream".
 */
 pub fn detect_content_type(data: &[u8]) -> &'static str {
+    detect_content_type_in(data, SniffContext::Browsing)
+}
+
+/**
+SniffContext mirrors the spec's notion of "sniffing in a context":
+https://mimesniff.spec.whatwg.org/#sniffing-in-a-context. A caller that
+already knows the class of resource it is loading (an `<img>`, a `<video>`,
+a `@font-face`, a `<track>`) can restrict the sniffer to the matching
+signature group, so that e.g. an HTML signature occurring inside image
+bytes is never mistaken for the resource's type.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SniffContext {
+    // No context restriction: consult the full signature table.
+    Browsing,
+    // Only image signatures are consulted.
+    Image,
+    // Only audio-or-video signatures are consulted.
+    AudioVideo,
+    // Only font signatures are consulted.
+    Font,
+    // No signature table applies; the spec falls back to "text/vtt".
+    TextTrack,
+}
+
+/**
+detect_content_type_in implements
+https://mimesniff.spec.whatwg.org/#context-specific-sniffing-algorithm: it
+behaves like detect_content_type but, outside of SniffContext::Browsing,
+only consults the signature group matching the context. If none of that
+group's signatures match, it falls back to "application/octet-stream" (or
+"text/vtt" for SniffContext::TextTrack) rather than matching signatures
+from an unrelated group.
+*/
+pub fn detect_content_type_in(data: &[u8], context: SniffContext) -> &'static str {
+    let group = match context {
+        SniffContext::Browsing => {
+            return detect_content_type_matching(data, |_| true)
+                .unwrap_or("application/octet-stream")
+        }
+        SniffContext::Image => SniffGroup::Image,
+        SniffContext::AudioVideo => SniffGroup::AudioVideo,
+        SniffContext::Font => SniffGroup::Font,
+        SniffContext::TextTrack => return "text/vtt",
+    };
+    detect_content_type_matching(data, |g| g == group).unwrap_or("application/octet-stream")
+}
+
+/**
+sniff_mime_type implements the full "MIME type sniffing algorithm" described
+at https://mimesniff.spec.whatwg.org/#mime-type-sniffing-algorithm (sections
+7 and 8), taking into account a server-supplied Content-Type and the
+resource's no-sniff flag. Unlike detect_content_type, which always runs the
+unknown-MIME-type signature table, this honors a supplied type: an XML or
+HTML type is trusted as-is, an image/audio/video type is only refined within
+its own signature group, and no_sniff makes any other supplied type be
+returned as-is. A missing, unparsable, or unknown supplied type
+("unknown/unknown", "application/unknown", "*/*") is always sniffed with
+the full signature table, whatever no_sniff says.
+*/
+pub fn sniff_mime_type(data: &[u8], supplied_type: Option<&str>, no_sniff: bool) -> String {
+    let supplied_type = match supplied_type.map(str::trim) {
+        Some(t) if !t.is_empty() => t,
+        _ => return detect_content_type(data).to_string(),
+    };
+
+    // An unparsable supplied type (e.g. missing the "/") carries no usable
+    // information, so it is treated the same as no supplied type at all.
+    let mime = match MimeType::parse(supplied_type) {
+        Some(mime) => mime,
+        None => return detect_content_type(data).to_string(),
+    };
+    let essence = mime.essence();
+
+    if essence == "unknown/unknown" || essence == "application/unknown" || essence == "*/*" {
+        return detect_content_type(data).to_string();
+    }
+    if no_sniff {
+        return supplied_type.to_string();
+    }
+    if essence == "text/html" {
+        if let Some(feed_ct) = sniff_mislabeled_feed(data) {
+            return feed_ct.to_string();
+        }
+        return supplied_type.to_string();
+    }
+    if is_xml_mime_type(&essence) {
+        return supplied_type.to_string();
+    }
+    if is_image_mime_type(&essence) {
+        return detect_content_type_matching(data, |g| g == SniffGroup::Image)
+            .map(str::to_string)
+            .unwrap_or_else(|| supplied_type.to_string());
+    }
+    if is_audio_or_video_mime_type(&essence) {
+        return detect_content_type_matching(data, |g| g == SniffGroup::AudioVideo)
+            .map(str::to_string)
+            .unwrap_or_else(|| supplied_type.to_string());
+    }
+    if has_apache_bug_flag(&mime) {
+        return sniff_apache_bug(data).to_string();
+    }
+    supplied_type.to_string()
+}
+
+// has_apache_bug_flag implements the "Apache bug flag" step of
+// https://mimesniff.spec.whatwg.org/#supplied-mime-type-detection-algorithm:
+// older Apache servers mislabel arbitrary binary responses as text/plain,
+// optionally with a charset of ISO-8859-1, iso-8859-1, or UTF-8 and no other
+// parameters, so such a supplied type cannot be trusted outright. This
+// matches on the parsed essence and charset rather than the raw header
+// text, so equivalent headers written differently (no space after the
+// ";", a differently-cased parameter name, ...) are still recognized.
+fn has_apache_bug_flag(mime: &MimeType) -> bool {
+    if mime.essence() != "text/plain" {
+        return false;
+    }
+    match mime.parameters.as_slice() {
+        [] => true,
+        [(key, value)] if key == "charset" => {
+            matches!(value.as_str(), "ISO-8859-1" | "iso-8859-1" | "UTF-8")
+        }
+        _ => false,
+    }
+}
+
+// sniff_apache_bug implements the binary-or-plain-text decision run when
+// has_apache_bug_flag is set: https://mimesniff.spec.whatwg.org/#rules-for-text-or-binary.
+// Unlike TextSig, which only looks from the first non-whitespace byte
+// onward while scanning the unknown-type signature table, this checks the
+// whole prefix, matching the spec's supplied-type algorithm.
+fn sniff_apache_bug(data: &[u8]) -> &'static str {
+    let mut data = data;
+    if data.len() > SNIFF_LEN {
+        data = &data[..SNIFF_LEN];
+    }
+    if data.iter().any(|&b| is_binary_data_byte(b)) {
+        "application/octet-stream"
+    } else {
+        "text/plain"
+    }
+}
+
+/**
+detect_content_type_parsed behaves like detect_content_type but returns a
+structured MimeType instead of a raw string, so callers can read the
+`charset` (or any other) parameter without re-parsing the result.
+*/
+pub fn detect_content_type_parsed(data: &[u8]) -> MimeType {
+    MimeType::parse(detect_content_type(data))
+        .expect("the signature table only yields well-formed MIME types")
+}
+
+/**
+MimeType is a parsed "type/subtype; key=value; ..." MIME type, per
+https://mimesniff.spec.whatwg.org/#parsing-a-mime-type. `type_` and
+`subtype` are lowercased; parameter values are unquoted but otherwise kept
+verbatim, and a parameter key is lowercased but not value.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MimeType {
+    pub type_: String,
+    pub subtype: String,
+    pub parameters: Vec<(String, String)>,
+}
+
+impl MimeType {
+    // parse splits s into a type, a subtype, and ;-separated parameters. It
+    // returns None if s has no '/' separating a non-empty type from a
+    // non-empty subtype.
+    pub fn parse(s: &str) -> Option<MimeType> {
+        let mut parts = split_mime_parts(s);
+        let essence = parts.next()?;
+        let (type_, subtype) = essence.split_once('/')?;
+        let type_ = type_.trim().to_ascii_lowercase();
+        let subtype = subtype.trim().to_ascii_lowercase();
+        if type_.is_empty() || subtype.is_empty() {
+            return None;
+        }
+
+        let mut parameters = Vec::new();
+        for param in parts {
+            let (key, value) = match param.split_once('=') {
+                Some((key, value)) => (key.trim().to_ascii_lowercase(), unquote(value.trim())),
+                None => continue,
+            };
+            if key.is_empty() {
+                continue;
+            }
+            parameters.push((key, value));
+        }
+
+        Some(MimeType {
+            type_,
+            subtype,
+            parameters,
+        })
+    }
+
+    // essence returns the "type/subtype" part, with no parameters.
+    pub fn essence(&self) -> String {
+        format!("{}/{}", self.type_, self.subtype)
+    }
+
+    // parameter looks up a parameter value by (case-insensitive) key, e.g.
+    // "charset".
+    pub fn parameter(&self, key: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+// split_mime_parts splits s on top-level `;` bytes, ignoring any `;` that
+// occurs inside a double-quoted parameter value. A backslash inside quotes
+// escapes the following byte, so a `\"` can't end the quoted value early —
+// matching the escaping that `unquote` reverses.
+fn split_mime_parts(s: &str) -> impl Iterator<Item = &str> {
+    let mut parts = Vec::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, b) in s.bytes().enumerate() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match b {
+            b'\\' if in_quotes => escaped = true,
+            b'"' => in_quotes = !in_quotes,
+            b';' if !in_quotes => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts.into_iter()
+}
+
+// unquote strips a leading and trailing double quote from a parameter
+// value, if present, and unescapes `\"` and `\\` within it.
+fn unquote(s: &str) -> String {
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        s[1..s.len() - 1].replace("\\\"", "\"").replace("\\\\", "\\")
+    } else {
+        s.to_string()
+    }
+}
+
+/**
+SniffReader wraps a `Read` so its content type can be sniffed without
+buffering the entire stream in memory. It transparently reads and buffers
+up to `SNIFF_LEN` bytes of the underlying reader the first time any byte is
+pulled through it, runs detect_content_type over that buffer, then replays
+the buffered prefix followed by the rest of the stream to its own readers
+unchanged. `content_type` only reflects the result of that first read: it
+returns `None` until enough bytes have been buffered (or the stream has
+reached EOF).
+*/
+pub struct SniffReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    buf_pos: usize,
+    inner_eof: bool,
+    content_type: Option<&'static str>,
+}
+
+impl<R: io::Read> SniffReader<R> {
+    pub fn new(inner: R) -> SniffReader<R> {
+        SniffReader {
+            inner,
+            buf: Vec::new(),
+            buf_pos: 0,
+            inner_eof: false,
+            content_type: None,
+        }
+    }
+
+    /// content_type returns the sniffed MIME type, or `None` if not enough
+    /// of the stream has been read yet to decide. Call `.read()` (directly
+    /// or via e.g. `io::copy`) until this returns `Some`.
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type
+    }
+
+    // fill buffers up to SNIFF_LEN bytes from the inner reader and computes
+    // content_type. It is a no-op once content_type has already been set.
+    // On an I/O error, whatever was already read from `inner` (in this call
+    // or an earlier one) is kept in the buffer rather than discarded, so a
+    // caller that retries after a transient error still gets every byte
+    // `inner` ever produced, in order, exactly once.
+    fn fill(&mut self) -> io::Result<()> {
+        if self.content_type.is_some() {
+            return Ok(());
+        }
+
+        let start = self.buf.len();
+        self.buf.resize(SNIFF_LEN, 0);
+        let mut filled = start;
+        let result = loop {
+            if filled >= SNIFF_LEN {
+                break Ok(());
+            }
+            match self.inner.read(&mut self.buf[filled..]) {
+                Ok(0) => {
+                    self.inner_eof = true;
+                    break Ok(());
+                }
+                Ok(n) => filled += n,
+                Err(e) => break Err(e),
+            }
+        };
+        self.buf.truncate(filled);
+        result?;
+        self.content_type = Some(detect_content_type(&self.buf));
+        Ok(())
+    }
+}
+
+impl<R: io::Read> io::Read for SniffReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        self.fill()?;
+
+        if self.buf_pos < self.buf.len() {
+            let n = out.len().min(self.buf.len() - self.buf_pos);
+            out[..n].copy_from_slice(&self.buf[self.buf_pos..self.buf_pos + n]);
+            self.buf_pos += n;
+            return Ok(n);
+        }
+
+        if self.inner_eof {
+            return Ok(0);
+        }
+        self.inner.read(out)
+    }
+}
+
+// detect_content_type_matching scans SNIFF_SIGNATURES in order, the same way
+// detect_content_type does, but only accepts a match whose group satisfies
+// `predicate`. This lets detect_content_type_in restrict the scan to a
+// single signature group (e.g. images) without duplicating the table walk.
+fn detect_content_type_matching<F>(data: &[u8], predicate: F) -> Option<&'static str>
+where
+    F: Fn(SniffGroup) -> bool,
+{
     let mut data = data;
     if data.len() > SNIFF_LEN {
         data = &data[..SNIFF_LEN];
@@ -21,15 +371,98 @@ pub fn detect_content_type(data: &[u8]) -> &'static str {
         first_non_ws += 1;
     }
 
-    for sig in SNIFF_SIGNATURES {
-        let ct = sig.sig_match(data, first_non_ws);
-        match ct {
-            Some(ct) => return ct,
-            _ => {}
+    for (group, sig) in SNIFF_SIGNATURES {
+        if !predicate(*group) {
+            continue;
+        }
+        if let Some(ct) = sig.sig_match(data, first_non_ws) {
+            return Some(ct);
         }
     }
 
-    return "application/octet-stream"; // fallback;
+    None
+}
+
+// is_xml_mime_type reports whether ct (already lowercased) is an XML MIME
+// type per https://mimesniff.spec.whatwg.org/#xml-mime-type.
+fn is_xml_mime_type(ct: &str) -> bool {
+    ct.ends_with("+xml") || ct == "text/xml" || ct == "application/xml"
+}
+
+// is_image_mime_type reports whether ct (already lowercased) is an image
+// MIME type per https://mimesniff.spec.whatwg.org/#image-mime-type.
+fn is_image_mime_type(ct: &str) -> bool {
+    ct.starts_with("image/")
+}
+
+// is_audio_or_video_mime_type reports whether ct (already lowercased) is an
+// audio or video MIME type per
+// https://mimesniff.spec.whatwg.org/#audio-or-video-mime-type.
+fn is_audio_or_video_mime_type(ct: &str) -> bool {
+    ct.starts_with("audio/") || ct.starts_with("video/") || ct == "application/ogg"
+}
+
+/**
+sniff_mislabeled_feed implements
+https://mimesniff.spec.whatwg.org/#sniffing-a-mislabeled-feed. Some servers
+mislabel RSS, Atom, or RDF (RSS 1.0) feeds as `text/html`; this walks past
+an optional UTF-8 BOM and any leading XML prologue noise (comments,
+processing instructions, doctype-like declarations, whitespace) and checks
+whether what follows is the start of a feed document. It returns `None`
+when the data does not look like a feed, leaving the supplied `text/html`
+type untouched. Every bounds check is explicit: per
+https://github.com/servo/servo/issues/7393, matching against truncated
+input must not panic or falsely succeed.
+*/
+fn sniff_mislabeled_feed(data: &[u8]) -> Option<&'static str> {
+    let mut data = data;
+    if data.len() > SNIFF_LEN {
+        data = &data[..SNIFF_LEN];
+    }
+
+    let mut pos = if data.starts_with(b"\xEF\xBB\xBF") { 3 } else { 0 };
+
+    loop {
+        if pos >= data.len() {
+            return None;
+        }
+        if data[pos..].starts_with(b"<!--") {
+            pos += find_subslice(&data[pos..], b"-->")? + 3;
+        } else if data[pos..].starts_with(b"<?") {
+            pos += find_subslice(&data[pos..], b"?>")? + 2;
+        } else if data[pos..].starts_with(b"<!") {
+            pos += find_subslice(&data[pos..], b">")? + 1;
+        } else if is_ws(data[pos]) {
+            pos += 1;
+        } else {
+            break;
+        }
+    }
+
+    let rest = &data[pos..];
+    if rest.starts_with(b"<rss") {
+        return Some("application/rss+xml");
+    }
+    if rest.starts_with(b"<feed") {
+        return Some("application/atom+xml");
+    }
+    if rest.starts_with(b"<rdf:RDF")
+        && find_subslice(rest, b"http://purl.org/rss/1.0/").is_some()
+        && find_subslice(rest, b"http://www.w3.org/1999/02/22-rdf-syntax-ns#").is_some()
+    {
+        return Some("application/rss+xml");
+    }
+
+    None
+}
+
+// find_subslice returns the offset of the first occurrence of `needle` in
+// `haystack`, bounds-checked so that truncated input never panics.
+fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.len() > haystack.len() {
+        return None;
+    }
+    haystack.windows(needle.len()).position(|w| w == needle)
 }
 
 /**
@@ -57,57 +490,70 @@ fn is_tt(b: u8) -> bool {
 /**
  * Data matching the table in section 6.
  * */
-const SNIFF_SIGNATURES: &[SniffSig] = &[
-    SniffSig::HTML(HTMLSig(b"<!DOCTYPE HTML")),
-    SniffSig::HTML(HTMLSig(b"<HTML")),
-    SniffSig::HTML(HTMLSig(b"<HEAD")),
-    SniffSig::HTML(HTMLSig(b"<SCRIPT")),
-    SniffSig::HTML(HTMLSig(b"<IFRAME")),
-    SniffSig::HTML(HTMLSig(b"<H1")),
-    SniffSig::HTML(HTMLSig(b"<DIV")),
-    SniffSig::HTML(HTMLSig(b"<FONT")),
-    SniffSig::HTML(HTMLSig(b"<TABLE")),
-    SniffSig::HTML(HTMLSig(b"<A")),
-    SniffSig::HTML(HTMLSig(b"<STYLE")),
-    SniffSig::HTML(HTMLSig(b"<TITLE")),
-    SniffSig::HTML(HTMLSig(b"<B")),
-    SniffSig::HTML(HTMLSig(b"<BODY")),
-    SniffSig::HTML(HTMLSig(b"<BR")),
-    SniffSig::HTML(HTMLSig(b"<P")),
-    SniffSig::HTML(HTMLSig(b"<!--")),
-    SniffSig::Masked(MaskedSig {
+// SniffGroup tags each signature with the context-restricted group it
+// belongs to, per https://mimesniff.spec.whatwg.org/#sniffing-in-a-context.
+// Signatures outside the three restrictable groups (HTML, XML, text,
+// archive, ...) are tagged General: they are only consulted in the
+// unrestricted Browsing context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SniffGroup {
+    General,
+    Image,
+    AudioVideo,
+    Font,
+}
+
+const SNIFF_SIGNATURES: &[(SniffGroup, SniffSig)] = &[
+    (SniffGroup::General, SniffSig::HTML(HTMLSig(b"<!DOCTYPE HTML"))),
+    (SniffGroup::General, SniffSig::HTML(HTMLSig(b"<HTML"))),
+    (SniffGroup::General, SniffSig::HTML(HTMLSig(b"<HEAD"))),
+    (SniffGroup::General, SniffSig::HTML(HTMLSig(b"<SCRIPT"))),
+    (SniffGroup::General, SniffSig::HTML(HTMLSig(b"<IFRAME"))),
+    (SniffGroup::General, SniffSig::HTML(HTMLSig(b"<H1"))),
+    (SniffGroup::General, SniffSig::HTML(HTMLSig(b"<DIV"))),
+    (SniffGroup::General, SniffSig::HTML(HTMLSig(b"<FONT"))),
+    (SniffGroup::General, SniffSig::HTML(HTMLSig(b"<TABLE"))),
+    (SniffGroup::General, SniffSig::HTML(HTMLSig(b"<A"))),
+    (SniffGroup::General, SniffSig::HTML(HTMLSig(b"<STYLE"))),
+    (SniffGroup::General, SniffSig::HTML(HTMLSig(b"<TITLE"))),
+    (SniffGroup::General, SniffSig::HTML(HTMLSig(b"<B"))),
+    (SniffGroup::General, SniffSig::HTML(HTMLSig(b"<BODY"))),
+    (SniffGroup::General, SniffSig::HTML(HTMLSig(b"<BR"))),
+    (SniffGroup::General, SniffSig::HTML(HTMLSig(b"<P"))),
+    (SniffGroup::General, SniffSig::HTML(HTMLSig(b"<!--"))),
+    (SniffGroup::General, SniffSig::Masked(MaskedSig {
         mask: b"\xFF\xFF\xFF\xFF\xFF",
         pat: b"<?xml",
         skip_ws: true,
         ct: "text/xml; charset=utf-8",
-    }),
-    SniffSig::Exact(ExactSig {
+    })),
+    (SniffGroup::General, SniffSig::Exact(ExactSig {
         sig: b"%PDF-",
         ct: "application/pdf",
-    }),
-    SniffSig::Exact(ExactSig {
+    })),
+    (SniffGroup::General, SniffSig::Exact(ExactSig {
         sig: b"%!PS-Adobe-",
         ct: "application/postsript",
-    }),
+    })),
     // UTF BOMs.
-    SniffSig::Masked(MaskedSig {
+    (SniffGroup::General, SniffSig::Masked(MaskedSig {
         mask: b"\xFF\xFF\x00\x00",
         pat: b"\xFE\xFF\x00\x00",
         skip_ws: false,
         ct: "text/plain; charset=utf-16be",
-    }),
-    SniffSig::Masked(MaskedSig {
+    })),
+    (SniffGroup::General, SniffSig::Masked(MaskedSig {
         mask: b"\xFF\xFF\x00\x00",
         pat: b"\xFF\xFE\x00\x00",
         skip_ws: false,
         ct: "text/plain; charset=utf-16le",
-    }),
-    SniffSig::Masked(MaskedSig {
+    })),
+    (SniffGroup::General, SniffSig::Masked(MaskedSig {
         mask: b"\xFF\xFF\xFF\x00",
         pat: b"\xEF\xBB\xBF\x00",
         skip_ws: false,
         ct: "text/plain; charset=utf-8",
-    }),
+    })),
 
     // Image types
     // For posterity, we originally returned "image/vnd.microsoft.icon" from
@@ -115,154 +561,154 @@ const SNIFF_SIGNATURES: &[SniffSig] = &[
     // https://codereview.appspot.com/4746042
     // but that has since been replaced with "image/x-icon" in Section 6.2
     // of https://mimesniff.spec.whatwg.org/#matching-an-image-type-pattern
-    SniffSig::Exact(ExactSig {
+    (SniffGroup::Image, SniffSig::Exact(ExactSig {
         sig: b"\x00\x00\x01\x00",
         ct: "image/x-icon",
-    }),
-    SniffSig::Exact(ExactSig {
+    })),
+    (SniffGroup::Image, SniffSig::Exact(ExactSig {
         sig: b"\x00\x00\x02\x00",
         ct: "image/x-icon",
-    }),
-    SniffSig::Exact(ExactSig {
+    })),
+    (SniffGroup::Image, SniffSig::Exact(ExactSig {
         sig: b"BM",
         ct: "image/bmp",
-    }),
-    SniffSig::Exact(ExactSig {
+    })),
+    (SniffGroup::Image, SniffSig::Exact(ExactSig {
         sig: b"GIF87a",
         ct: "image/gif",
-    }),
-    SniffSig::Exact(ExactSig {
+    })),
+    (SniffGroup::Image, SniffSig::Exact(ExactSig {
         sig: b"GIF89a",
         ct: "image/gif",
-    }),
-    SniffSig::Masked(MaskedSig {
+    })),
+    (SniffGroup::Image, SniffSig::Masked(MaskedSig {
         mask: b"\xFF\xFF\xFF\xFF\x00\x00\x00\x00\xFF\xFF\xFF\xFF\xFF\xFF",
         pat: b"RIFF\x00\x00\x00\x00WEBPVP",
         skip_ws: false,
         ct: "image/webp",
-    }),
-    SniffSig::Exact(ExactSig {
+    })),
+    (SniffGroup::Image, SniffSig::Exact(ExactSig {
         sig: b"\x89PNG\x0D\x0A\x1A\x0A",
         ct: "image/png",
-    }),
-    SniffSig::Exact(ExactSig {
+    })),
+    (SniffGroup::Image, SniffSig::Exact(ExactSig {
         sig: b"\xFF\xD8\xFF",
         ct: "image/jpeg",
-    }),
+    })),
 
     // Audio and Video types
     // Enforce the pattern match ordering as prescribed in
     // https://mimesniff.spec.whatwg.org/#matching-an-audio-or-video-type-pattern
-    SniffSig::Masked(MaskedSig {
+    (SniffGroup::AudioVideo, SniffSig::Masked(MaskedSig {
         mask: b"\xFF\xFF\xFF\xFF",
         pat: b".snd",
         skip_ws: false,
         ct: "audio/basic",
-    }),
-    SniffSig::Masked(MaskedSig {
+    })),
+    (SniffGroup::AudioVideo, SniffSig::Masked(MaskedSig {
         mask: b"\xFF\xFF\xFF\xFF\x00\x00\x00\x00\xFF\xFF\xFF\xFF",
         pat: b"FORM\x00\x00\x00\x00AIFF",
         skip_ws: false,
         ct: "audio/aiff",
-    }),
-    SniffSig::Masked(MaskedSig {
+    })),
+    (SniffGroup::AudioVideo, SniffSig::Masked(MaskedSig {
         mask: b"\xFF\xFF\xFF",
         pat: b"ID3",
         skip_ws: false,
         ct: "audio/mpeg",
-    }),
-    SniffSig::Masked(MaskedSig {
+    })),
+    (SniffGroup::AudioVideo, SniffSig::Masked(MaskedSig {
         mask: b"\xFF\xFF\xFF\xFF\xFF",
         pat: b"OggS\x00",
         skip_ws: false,
         ct: "application/ogg",
-    }),
-    SniffSig::Masked(MaskedSig {
+    })),
+    (SniffGroup::AudioVideo, SniffSig::Masked(MaskedSig {
         mask: b"\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF",
         pat: b"MThd\x00\x00\x00\x06",
         skip_ws: false,
         ct: "audio/midi",
-    }),
-    SniffSig::Masked(MaskedSig {
+    })),
+    (SniffGroup::AudioVideo, SniffSig::Masked(MaskedSig {
         mask: b"\xFF\xFF\xFF\xFF\x00\x00\x00\x00\xFF\xFF\xFF\xFF",
         pat: b"RIFF\x00\x00\x00\x00AVI ",
         skip_ws: false,
         ct: "video/avi",
-    }),
-    SniffSig::Masked(MaskedSig {
+    })),
+    (SniffGroup::AudioVideo, SniffSig::Masked(MaskedSig {
         mask: b"\xFF\xFF\xFF\xFF\x00\x00\x00\x00\xFF\xFF\xFF\xFF",
         pat: b"RIFF\x00\x00\x00\x00WAVE",
         skip_ws: false,
         ct: "audio/wave",
-    }),
+    })),
     // 6.2.0.2. video/mp4
-    SniffSig::MP4(MP4Sig{}),
+    (SniffGroup::AudioVideo, SniffSig::MP4(MP4Sig{})),
     // 6.2.0.3. video/webm
-    SniffSig::Exact(ExactSig{
+    (SniffGroup::AudioVideo, SniffSig::Exact(ExactSig{
         sig: b"\x1A\x45\xDF\xA3",
         ct: "video/webm",
-    }),
+    })),
 
     // Font types
-    SniffSig::Masked(MaskedSig{
+    (SniffGroup::Font, SniffSig::Masked(MaskedSig{
         // // 34 NULL bytes followed by the string "LP"
         pat: b"\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00LP",
         // 34 NULL bytes followed by \xF\xF
         mask: b"\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\xFF\xFF",
         skip_ws: false,
         ct: "application/vnd.ms-fontobject",
-    }),
-    SniffSig::Exact(ExactSig{
+    })),
+    (SniffGroup::Font, SniffSig::Exact(ExactSig{
         sig: b"\x00\x01\x00\x00",
         ct: "font/ttf",
-    }),
-    SniffSig::Exact(ExactSig{
+    })),
+    (SniffGroup::Font, SniffSig::Exact(ExactSig{
         sig: b"OTTO",
         ct: "font/otf",
-    }),
-    SniffSig::Exact(ExactSig{
+    })),
+    (SniffGroup::Font, SniffSig::Exact(ExactSig{
         sig: b"ttcf",
         ct: "font/collection",
-    }),
-    SniffSig::Exact(ExactSig{
+    })),
+    (SniffGroup::Font, SniffSig::Exact(ExactSig{
         sig: b"wOFF",
         ct: "font/woff",
-    }),
-    SniffSig::Exact(ExactSig{
+    })),
+    (SniffGroup::Font, SniffSig::Exact(ExactSig{
         sig: b"wOF2",
         ct: "font/woff2",
-    }),
+    })),
 
     // Archive types
-    SniffSig::Exact(ExactSig{
+    (SniffGroup::General, SniffSig::Exact(ExactSig{
         sig: b"\x1F\x8B\x08",
         ct: "application/x-gzip",
-    }),
-    SniffSig::Exact(ExactSig{
+    })),
+    (SniffGroup::General, SniffSig::Exact(ExactSig{
         sig: b"PK\x03\x04",
         ct: "application/zip",
-    }),
+    })),
     // RAR's signatures are incorrectly defined by the MIME spec as per
 	//    https://github.com/whatwg/mimesniff/issues/63
 	// However, RAR Labs correctly defines it at:
 	//    https://www.rarlab.com/technote.htm#rarsign
 	// so we use the definition from RAR Labs.
     // TODO: do whatever the spec ends up doing.
-    SniffSig::Exact(ExactSig{
+    (SniffGroup::General, SniffSig::Exact(ExactSig{
         sig: b"Rar!\x1A\x07\x00", // RAR v1.5-v4.0
         ct: "application/x-rar-compressed",
-    }),
-    SniffSig::Exact(ExactSig{
+    })),
+    (SniffGroup::General, SniffSig::Exact(ExactSig{
         sig: b"Rar!\x1A\x07\x01\x00", // RAR v5+
         ct: "application/x-rar-compressed",
-    }),
+    })),
 
-    SniffSig::Exact(ExactSig{
+    (SniffGroup::General, SniffSig::Exact(ExactSig{
         sig: b"\x00\x61\x73\x6D",
         ct:  "application/wasm",
-    }),
+    })),
 
-    SniffSig::Text(TextSig{}), // should be last
+    (SniffGroup::General, SniffSig::Text(TextSig{})), // should be last
 ];
 
 #[derive(Debug)]
@@ -399,16 +845,21 @@ impl TextSig {
     fn sig_match(&self, data: &[u8], first_non_ws: usize) -> Option<&'static str> {
         // c.f. section 5, step 4.
         let data = &data[first_non_ws..];
-        for b in data {
-            let b = *b;
-            if b <= 0x08 || b == 0x0B || (0x0E <= b && b <= 0x1A) || (0x1C <= b && b <= 0x1F) {
-                return None;
-            }
+        if data.iter().any(|&b| is_binary_data_byte(b)) {
+            return None;
         }
         return Some("text/plain; charset=utf-8");
     }
 }
 
+// is_binary_data_byte reports whether b is one of the "binary data byte"
+// values from https://mimesniff.spec.whatwg.org/#terminology (c.f. section
+// 5, step 4), which TextSig treats as disqualifying a buffer from being
+// plain text.
+fn is_binary_data_byte(b: u8) -> bool {
+    b <= 0x08 || b == 0x0B || (0x0E..=0x1A).contains(&b) || (0x1C..=0x1F).contains(&b)
+}
+
 fn decode_big_endian_utf32(b: &[u8]) -> u32 {
     (b[3] as u32) | (b[2] as u32) << 8 | (b[1] as u32) << 16 | (b[0] as u32) << 24
 }
@@ -498,14 +949,439 @@ mod tests {
 
         for tt in SNIFF_TESTS {
             let ct = detect_content_type(tt.data());
-            if !ct.eq(tt.content_type()) {
-                panic!(format!(
-                    "{}: detect_content_type = {}, want {}",
-                    tt.desc(),
-                    ct,
-                    tt.content_type(),
-                ));
+            assert_eq!(ct, tt.content_type(), "{}: detect_content_type", tt.desc());
+        }
+    }
+
+    struct SniffMimeTypeTest(
+        &'static str,        // desc
+        &'static [u8],       // data
+        Option<&'static str>, // supplied type
+        bool,                // no_sniff
+        &'static str,        // want
+    );
+
+    static SNIFF_MIME_TYPE_TESTS: &[SniffMimeTypeTest] = &[
+        SniffMimeTypeTest(
+            "No supplied type falls back to sniffing",
+            b"<HTML>",
+            None,
+            false,
+            "text/html; charset=utf-8",
+        ),
+        SniffMimeTypeTest(
+            "unknown/unknown supplied type triggers sniffing",
+            b"%PDF-",
+            Some("unknown/unknown"),
+            false,
+            "application/pdf",
+        ),
+        SniffMimeTypeTest(
+            "*/* supplied type triggers sniffing",
+            b"GIF89a",
+            Some("*/*"),
+            false,
+            "image/gif",
+        ),
+        SniffMimeTypeTest(
+            "no_sniff returns supplied type unchanged",
+            b"%PDF-",
+            Some("text/plain"),
+            true,
+            "text/plain",
+        ),
+        SniffMimeTypeTest(
+            "XML supplied type is trusted",
+            b"<HTML>",
+            Some("application/rss+xml"),
+            false,
+            "application/rss+xml",
+        ),
+        SniffMimeTypeTest(
+            "text/xml supplied type is trusted",
+            b"<HTML>",
+            Some("text/xml"),
+            false,
+            "text/xml",
+        ),
+        SniffMimeTypeTest(
+            "text/html supplied type is trusted",
+            b"%PDF-",
+            Some("text/html"),
+            false,
+            "text/html",
+        ),
+        SniffMimeTypeTest(
+            "image supplied type is refined by sniffing",
+            b"GIF89a",
+            Some("image/png"),
+            false,
+            "image/gif",
+        ),
+        SniffMimeTypeTest(
+            "image supplied type falls back when no image signature matches",
+            b"not an image",
+            Some("image/png"),
+            false,
+            "image/png",
+        ),
+        SniffMimeTypeTest(
+            "audio/video supplied type is refined by sniffing",
+            b"\x1A\x45\xDF\xA3",
+            Some("video/mpeg"),
+            false,
+            "video/webm",
+        ),
+        SniffMimeTypeTest(
+            "other supplied type is trusted as-is",
+            b"<HTML>",
+            Some("application/json"),
+            false,
+            "application/json",
+        ),
+        SniffMimeTypeTest(
+            "text/html supplied type is corrected for a mislabeled RSS feed",
+            b"<?xml version=\"1.0\"?><rss version=\"2.0\"><channel></channel></rss>",
+            Some("text/html"),
+            false,
+            "application/rss+xml",
+        ),
+        SniffMimeTypeTest(
+            "text/html supplied type is corrected for a mislabeled Atom feed",
+            b"<?xml version=\"1.0\"?><feed xmlns=\"http://www.w3.org/2005/Atom\"></feed>",
+            Some("text/html"),
+            false,
+            "application/atom+xml",
+        ),
+        SniffMimeTypeTest(
+            "text/html supplied type is trusted when the body is not a feed",
+            b"<HTML><BODY>hi</BODY></HTML>",
+            Some("text/html"),
+            false,
+            "text/html",
+        ),
+        SniffMimeTypeTest(
+            "Apache-bug text/plain over binary data resolves to octet-stream",
+            b"\x89PNG\x0D\x0A\x1A\x0A",
+            Some("text/plain"),
+            false,
+            "application/octet-stream",
+        ),
+        SniffMimeTypeTest(
+            "Apache-bug text/plain; charset=UTF-8 over binary data resolves to octet-stream",
+            b"\x89PNG\x0D\x0A\x1A\x0A",
+            Some("text/plain; charset=UTF-8"),
+            false,
+            "application/octet-stream",
+        ),
+        SniffMimeTypeTest(
+            "Apache-bug text/plain over real text is trusted as plain text",
+            b"just some plain text",
+            Some("text/plain"),
+            false,
+            "text/plain",
+        ),
+        SniffMimeTypeTest(
+            "text/plain with an unrecognized charset is not the Apache bug",
+            b"\x89PNG\x0D\x0A\x1A\x0A",
+            Some("text/plain; charset=windows-1252"),
+            false,
+            "text/plain; charset=windows-1252",
+        ),
+        SniffMimeTypeTest(
+            "Apache-bug text/plain;charset=UTF-8 with no space over binary data resolves to octet-stream",
+            b"\x89PNG\x0D\x0A\x1A\x0A",
+            Some("text/plain;charset=UTF-8"),
+            false,
+            "application/octet-stream",
+        ),
+    ];
+
+    #[test]
+    fn test_sniff_mime_type() {
+        use super::sniff_mime_type;
+
+        for tt in SNIFF_MIME_TYPE_TESTS {
+            let got = sniff_mime_type(tt.1, tt.2, tt.3);
+            assert_eq!(got, tt.4, "{}: sniff_mime_type", tt.0);
+        }
+    }
+
+    struct SniffInContextTest(
+        &'static str,       // desc
+        &'static [u8],      // data
+        super::SniffContext, // context
+        &'static str,       // want
+    );
+
+    static SNIFF_IN_CONTEXT_TESTS: &[SniffInContextTest] = &[
+        SniffInContextTest(
+            "Browsing context sniffs the full table",
+            b"<HTML>",
+            super::SniffContext::Browsing,
+            "text/html; charset=utf-8",
+        ),
+        SniffInContextTest(
+            "Image context matches an image signature",
+            b"GIF89a",
+            super::SniffContext::Image,
+            "image/gif",
+        ),
+        SniffInContextTest(
+            "Image context ignores non-image signatures",
+            b"<HTML>",
+            super::SniffContext::Image,
+            "application/octet-stream",
+        ),
+        SniffInContextTest(
+            "AudioVideo context matches an audio/video signature",
+            b"\x1A\x45\xDF\xA3",
+            super::SniffContext::AudioVideo,
+            "video/webm",
+        ),
+        SniffInContextTest(
+            "AudioVideo context ignores image signatures",
+            b"GIF89a",
+            super::SniffContext::AudioVideo,
+            "application/octet-stream",
+        ),
+        SniffInContextTest(
+            "Font context matches a font signature",
+            b"wOFF\x00\x01\x00\x00",
+            super::SniffContext::Font,
+            "font/woff",
+        ),
+        SniffInContextTest(
+            "TextTrack context always falls back to text/vtt",
+            b"GIF89a",
+            super::SniffContext::TextTrack,
+            "text/vtt",
+        ),
+    ];
+
+    #[test]
+    fn test_detect_content_type_in() {
+        use super::detect_content_type_in;
+
+        for tt in SNIFF_IN_CONTEXT_TESTS {
+            let got = detect_content_type_in(tt.1, tt.2);
+            assert_eq!(got, tt.3, "{}: detect_content_type_in", tt.0);
+        }
+    }
+
+    struct SniffMislabeledFeedTest(
+        &'static str,  // desc
+        &'static [u8], // data
+        Option<&'static str>, // want
+    );
+
+    static SNIFF_MISLABELED_FEED_TESTS: &[SniffMislabeledFeedTest] = &[
+        SniffMislabeledFeedTest(
+            "RSS feed",
+            b"<?xml version=\"1.0\"?><rss version=\"2.0\"></rss>",
+            Some("application/rss+xml"),
+        ),
+        SniffMislabeledFeedTest(
+            "RSS feed behind a UTF-8 BOM and a comment",
+            b"\xEF\xBB\xBF<!-- hi --><rss version=\"2.0\"></rss>",
+            Some("application/rss+xml"),
+        ),
+        SniffMislabeledFeedTest(
+            "Atom feed",
+            b"<?xml version=\"1.0\"?><feed xmlns=\"http://www.w3.org/2005/Atom\"></feed>",
+            Some("application/atom+xml"),
+        ),
+        SniffMislabeledFeedTest(
+            "RDF feed with both namespaces present",
+            b"<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\" xmlns=\"http://purl.org/rss/1.0/\"></rdf:RDF>",
+            Some("application/rss+xml"),
+        ),
+        SniffMislabeledFeedTest(
+            "RDF document missing the RSS 1.0 namespace is not a feed",
+            b"<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\"></rdf:RDF>",
+            None,
+        ),
+        SniffMislabeledFeedTest("Plain HTML is not a feed", b"<HTML><BODY></BODY></HTML>", None),
+        SniffMislabeledFeedTest("Empty input is not a feed", b"", None),
+        SniffMislabeledFeedTest(
+            "Truncated comment does not panic or match",
+            b"<!-- unterminated",
+            None,
+        ),
+        SniffMislabeledFeedTest(
+            "Truncated processing instruction does not panic or match",
+            b"<?xml version=\"1.0\"",
+            None,
+        ),
+        SniffMislabeledFeedTest(
+            "Truncated declaration does not panic or match",
+            b"<!DOCTYPE html",
+            None,
+        ),
+    ];
+
+    #[test]
+    fn test_sniff_mislabeled_feed() {
+        use super::sniff_mislabeled_feed;
+
+        for tt in SNIFF_MISLABELED_FEED_TESTS {
+            let got = sniff_mislabeled_feed(tt.1);
+            assert_eq!(got, tt.2, "{}: sniff_mislabeled_feed", tt.0);
+        }
+    }
+
+    #[test]
+    fn test_mime_type_parse() {
+        use super::MimeType;
+
+        let mime = MimeType::parse("Text/HTML; Charset=\"utf-8\"; Boundary=xyz").unwrap();
+        assert_eq!(mime.type_, "text");
+        assert_eq!(mime.subtype, "html");
+        assert_eq!(mime.essence(), "text/html");
+        assert_eq!(mime.parameter("charset"), Some("utf-8"));
+        assert_eq!(mime.parameter("CHARSET"), Some("utf-8"));
+        assert_eq!(mime.parameter("boundary"), Some("xyz"));
+        assert_eq!(mime.parameter("missing"), None);
+
+        let mime = MimeType::parse("application/json").unwrap();
+        assert_eq!(mime.essence(), "application/json");
+        assert!(mime.parameters.is_empty());
+
+        assert!(MimeType::parse("not-a-mime-type").is_none());
+        assert!(MimeType::parse("/subtype-only").is_none());
+        assert!(MimeType::parse("type-only/").is_none());
+
+        let mime = MimeType::parse(r#"application/json; foo="a\"b"; bar=baz"#).unwrap();
+        assert_eq!(mime.parameter("foo"), Some("a\"b"));
+        assert_eq!(mime.parameter("bar"), Some("baz"));
+    }
+
+    #[test]
+    fn test_detect_content_type_parsed() {
+        use super::detect_content_type_parsed;
+
+        let mime = detect_content_type_parsed(b"\x89PNG\x0D\x0A\x1A\x0A");
+        assert_eq!(mime.essence(), "image/png");
+
+        let mime = detect_content_type_parsed(b"plain text file");
+        assert_eq!(mime.essence(), "text/plain");
+        assert_eq!(mime.parameter("charset"), Some("utf-8"));
+    }
+
+    #[test]
+    fn test_sniff_reader() {
+        use super::SniffReader;
+        use std::io::Read;
+
+        let body = b"\x89PNG\x0D\x0A\x1A\x0Arest of the file follows";
+        let mut reader = SniffReader::new(&body[..]);
+        assert_eq!(reader.content_type(), None);
+
+        let mut got = Vec::new();
+        reader.read_to_end(&mut got).unwrap();
+        assert_eq!(got, body);
+        assert_eq!(reader.content_type(), Some("image/png"));
+    }
+
+    #[test]
+    fn test_sniff_reader_short_stream() {
+        use super::SniffReader;
+        use std::io::Read;
+
+        let body = b"<HTML>";
+        let mut reader = SniffReader::new(&body[..]);
+
+        let mut got = Vec::new();
+        reader.read_to_end(&mut got).unwrap();
+        assert_eq!(got, body);
+        assert_eq!(reader.content_type(), Some("text/html; charset=utf-8"));
+    }
+
+    #[test]
+    fn test_sniff_reader_small_reads_preserve_sniff() {
+        use super::SniffReader;
+        use std::io::Read;
+
+        let body = b"GIF89a...rest of the file follows, well past one byte";
+        let mut reader = SniffReader::new(&body[..]);
+
+        let mut got = Vec::new();
+        let mut chunk = [0u8; 1];
+        loop {
+            let n = reader.read(&mut chunk).unwrap();
+            if n == 0 {
+                break;
             }
+            got.extend_from_slice(&chunk[..n]);
         }
+        assert_eq!(got, body);
+        assert_eq!(reader.content_type(), Some("image/gif"));
+    }
+
+    // A reader that hands out a fixed sequence of chunks, one of which is an
+    // I/O error, to exercise SniffReader's behavior when `inner` fails partway
+    // through a fill and is then retried.
+    struct FlakyReader {
+        steps: std::collections::VecDeque<FlakyStep>,
+    }
+
+    enum FlakyStep {
+        Data(Vec<u8>),
+        Err,
+    }
+
+    impl std::io::Read for FlakyReader {
+        fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+            match self.steps.pop_front() {
+                None => Ok(0),
+                Some(FlakyStep::Err) => Err(std::io::Error::new(
+                    std::io::ErrorKind::Interrupted,
+                    "transient failure",
+                )),
+                Some(FlakyStep::Data(chunk)) => {
+                    let n = chunk.len().min(out.len());
+                    out[..n].copy_from_slice(&chunk[..n]);
+                    if n < chunk.len() {
+                        self.steps.push_front(FlakyStep::Data(chunk[n..].to_vec()));
+                    }
+                    Ok(n)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sniff_reader_retried_after_io_error() {
+        use super::SniffReader;
+        use std::io::Read;
+
+        let prefix = vec![b'A'; 100];
+        let suffix = vec![b'B'; 50];
+        let inner = FlakyReader {
+            steps: vec![
+                FlakyStep::Data(prefix.clone()),
+                FlakyStep::Err,
+                FlakyStep::Data(suffix.clone()),
+            ]
+            .into(),
+        };
+        let mut reader = SniffReader::new(inner);
+
+        let mut got = Vec::new();
+        let mut chunk = [0u8; 16];
+        loop {
+            match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => got.extend_from_slice(&chunk[..n]),
+                Err(e) => {
+                    assert_eq!(e.kind(), std::io::ErrorKind::Interrupted);
+                    continue;
+                }
+            }
+        }
+
+        let mut want = prefix;
+        want.extend_from_slice(&suffix);
+        assert_eq!(got, want);
     }
 }